@@ -0,0 +1,54 @@
+use rdkafka::{consumer::ConsumerContext, statistics::Statistics, ClientContext};
+use tracing::debug;
+
+/// Interval on which librdkafka emits a `stats` callback with a snapshot of
+/// the client's internal state.
+pub(crate) const STATISTICS_INTERVAL_MS: &str = "5000";
+
+pub(crate) const CONSUMER_LAG: &str = "kafka_consumer_lag";
+pub(crate) const MESSAGES_CONSUMED_RATE: &str = "kafka_messages_consumed_rate";
+pub(crate) const MESSAGES_PRODUCED_RATE: &str = "kafka_messages_produced_rate";
+pub(crate) const BROKER_RTT_AVG_US: &str = "kafka_broker_rtt_avg_us";
+pub(crate) const REBALANCE_COUNT: &str = "kafka_rebalance_count";
+
+/// `rdkafka` client context that republishes librdkafka's periodic
+/// statistics as Prometheus gauges. The message counters in `control.rs`
+/// only show what this consumer has processed; these gauges additionally
+/// expose how far it is falling behind the trace topic, which partition is
+/// lagging, and how the brokers themselves are responding.
+#[derive(Default)]
+pub(crate) struct MetricsConsumerContext;
+
+impl ClientContext for MetricsConsumerContext {
+    fn stats(&self, statistics: Statistics) {
+        debug!("Received Kafka client statistics");
+
+        for (topic_name, topic) in &statistics.topics {
+            for (partition_id, partition) in &topic.partitions {
+                if partition.consumer_lag < 0 {
+                    continue;
+                }
+                metrics::gauge!(
+                    CONSUMER_LAG,
+                    "topic" => topic_name.clone(),
+                    "partition" => partition_id.to_string()
+                )
+                .set(partition.consumer_lag as f64);
+            }
+        }
+
+        for broker in statistics.brokers.values() {
+            metrics::gauge!(BROKER_RTT_AVG_US, "broker" => broker.nodename.clone())
+                .set(broker.rtt.avg as f64);
+        }
+
+        metrics::gauge!(MESSAGES_CONSUMED_RATE).set(statistics.rxmsgs as f64);
+        metrics::gauge!(MESSAGES_PRODUCED_RATE).set(statistics.txmsgs as f64);
+
+        if let Some(consumer_group) = &statistics.cgrp {
+            metrics::gauge!(REBALANCE_COUNT).set(consumer_group.rebalance_cnt as f64);
+        }
+    }
+}
+
+impl ConsumerContext for MetricsConsumerContext {}
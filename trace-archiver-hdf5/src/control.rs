@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::{collections::HashMap, path::PathBuf, time::Duration};
 
 use anyhow::Result;
 use chrono::{DateTime, Utc};
@@ -6,7 +6,9 @@ use metrics::counter;
 use metrics_exporter_prometheus::PrometheusBuilder;
 use rdkafka::{
     consumer::{CommitMode, Consumer, StreamConsumer},
-    Message, Timestamp,
+    message::Headers,
+    topic_partition_list::TopicPartitionList,
+    Message, Offset, Timestamp,
 };
 use supermusr_common::metrics::{
     failures::{self, FailureKind},
@@ -18,26 +20,70 @@ use supermusr_streaming_types::{
         digitizer_analog_trace_message_buffer_has_identifier,
         root_as_digitizer_analog_trace_message,
     },
-    ecs_6s4t_run_stop_generated::run_stop_buffer_has_identifier,
-    ecs_pl72_run_start_generated::run_start_buffer_has_identifier,
+    ecs_6s4t_run_stop_generated::{root_as_run_stop_message, run_stop_buffer_has_identifier},
+    ecs_pl72_run_start_generated::{root_as_run_start_message, run_start_buffer_has_identifier},
 };
+use tokio::time;
 use tracing::{debug, info, warn};
 
-use crate::{file::TraceFile, ControlOpts};
+use crate::{
+    context::{
+        MetricsConsumerContext, BROKER_RTT_AVG_US, CONSUMER_LAG, MESSAGES_CONSUMED_RATE,
+        MESSAGES_PRODUCED_RATE, REBALANCE_COUNT, STATISTICS_INTERVAL_MS,
+    },
+    file::{Compression, TraceFile, TraceProvenance},
+    ControlOpts,
+};
+
+/// Interval on which offsets acknowledged by a successful file write are
+/// committed to Kafka when `--ack-on-write` is enabled.
+const ACK_COMMIT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Number of runs with a currently open HDF5 file.
+const OPEN_RUNS: &str = "open_runs";
 
 pub(crate) async fn run(control_args: ControlOpts) -> Result<()> {
+    let ack_on_write = control_args.ack_on_write;
     let common_args = control_args.common;
 
-    let consumer: StreamConsumer = supermusr_common::generate_kafka_client_config(
+    let mut client_config = supermusr_common::generate_kafka_client_config(
         &common_args.broker,
         &common_args.username,
         &common_args.password,
-    )
-    .set("group.id", &common_args.consumer_group)
-    .set("enable.partition.eof", "false")
-    .set("session.timeout.ms", "6000")
-    .set("enable.auto.commit", "false")
-    .create()?;
+    );
+    client_config
+        .set("group.id", &common_args.consumer_group)
+        .set("enable.partition.eof", "false")
+        .set("session.timeout.ms", "6000")
+        .set("enable.auto.commit", "false")
+        .set("security.protocol", &common_args.security_protocol)
+        .set("sasl.mechanisms", &common_args.sasl_mechanism);
+
+    if ack_on_write {
+        // librdkafka otherwise advances the "stored offset" to every message
+        // handed to the application, regardless of whether it has actually
+        // been written to disk. `commit_consumer_state`/auto-commit would
+        // then commit that stored offset, silently defeating `acked_offsets`
+        // tracking below on the one path (shutdown) it matters most for.
+        client_config.set("enable.auto.offset.store", "false");
+    }
+
+    if let Some(ca_location) = &common_args.ssl_ca_location {
+        client_config.set("ssl.ca.location", ca_location);
+    }
+    if let Some(certificate_location) = &common_args.ssl_certificate_location {
+        client_config.set("ssl.certificate.location", certificate_location);
+    }
+    if let Some(key_location) = &common_args.ssl_key_location {
+        client_config.set("ssl.key.location", key_location);
+    }
+    if let Some(key_password) = &common_args.ssl_key_password {
+        client_config.set("ssl.key.password", key_password);
+    }
+    client_config.set("statistics.interval.ms", STATISTICS_INTERVAL_MS);
+
+    let consumer: StreamConsumer<MetricsConsumerContext> =
+        client_config.create_with_context(MetricsConsumerContext)?;
 
     // Install exporter and register metrics
     let builder = PrometheusBuilder::new();
@@ -56,6 +102,36 @@ pub(crate) async fn run(control_args: ControlOpts) -> Result<()> {
         metrics::Unit::Count,
         "Number of failures encountered"
     );
+    metrics::describe_gauge!(
+        CONSUMER_LAG,
+        metrics::Unit::Count,
+        "Consumer lag per topic partition, as reported by librdkafka"
+    );
+    metrics::describe_gauge!(
+        MESSAGES_CONSUMED_RATE,
+        metrics::Unit::Count,
+        "Total messages consumed by the underlying Kafka client"
+    );
+    metrics::describe_gauge!(
+        MESSAGES_PRODUCED_RATE,
+        metrics::Unit::Count,
+        "Total messages produced by the underlying Kafka client"
+    );
+    metrics::describe_gauge!(
+        BROKER_RTT_AVG_US,
+        metrics::Unit::Microseconds,
+        "Average broker request round-trip time, as reported by librdkafka"
+    );
+    metrics::describe_gauge!(
+        REBALANCE_COUNT,
+        metrics::Unit::Count,
+        "Number of consumer group rebalances observed"
+    );
+    metrics::describe_gauge!(
+        OPEN_RUNS,
+        metrics::Unit::Count,
+        "Number of runs with a currently open HDF5 file"
+    );
 
     let topics_to_subscribe = [
         control_args.control_topic.as_str(),
@@ -64,113 +140,356 @@ pub(crate) async fn run(control_args: ControlOpts) -> Result<()> {
 
     consumer.subscribe(&topics_to_subscribe)?;
 
-    let mut file: Option<TraceFile> = None;
+    // Open HDF5 files keyed by run name, so overlapping runs (e.g. one per
+    // instrument) can each be recorded to their own file.
+    let mut open_files: HashMap<String, TraceFile> = HashMap::new();
+
+    // Tracks which run each digitizer currently belongs to, so an incoming
+    // trace message (which carries no run name of its own) can be routed to
+    // the correct open file. Populated from the digitizer list on a run
+    // start and released on the matching run stop.
+    let mut digitizer_runs: HashMap<u8, String> = HashMap::new();
+
+    // Highest offset per (topic, partition) whose corresponding trace has
+    // been durably written (pushed and flushed) to its run's HDF5 file, or
+    // which was discarded with nothing to write (see `ack_offset`).
+    // Only populated when `--ack-on-write` is set; control-topic offsets are
+    // never gated on a file write and are committed as soon as they are
+    // processed.
+    let mut acked_offsets: HashMap<(String, i32), i64> = HashMap::new();
+
+    let mut ack_commit_timer = time::interval(ACK_COMMIT_INTERVAL);
 
     loop {
-        match consumer.recv().await {
-            Err(e) => warn!("Kafka error: {}", e),
-            Ok(msg) => {
-                debug!(
-                    "key: '{:?}', topic: {}, partition: {}, offset: {}, timestamp: {:?}",
-                    msg.key(),
-                    msg.topic(),
-                    msg.partition(),
-                    msg.offset(),
-                    msg.timestamp()
-                );
-
-                if let Some(payload) = msg.payload() {
-                    if digitizer_analog_trace_message_buffer_has_identifier(payload) {
-                        // A message has been received from the trace topic.
-                        match root_as_digitizer_analog_trace_message(payload) {
-                            Ok(data) => {
-                                info!(
-                                    "Trace packet: dig. ID: {}, metadata: {:?}",
-                                    data.digitizer_id(),
-                                    data.metadata()
-                                );
-                                counter!(
-                                    MESSAGES_RECEIVED,
-                                    &[messages_received::get_label(MessageKind::Trace)]
-                                )
-                                .increment(1);
-
-                                if let Some(ref mut file) = file {
-                                    info!("Writing trace data to \"{}\"", file.filename());
-                                    if let Err(e) = file.push(&data) {
-                                        warn!("Failed to save traces to file: {}", e);
-                                        counter!(
-                                            FAILURES,
-                                            &[failures::get_label(FailureKind::FileWriteFailed)]
-                                        )
-                                        .increment(1);
+        tokio::select! {
+            _ = shutdown_signal() => {
+                info!("Shutdown signal received, finalizing current run.");
+                break;
+            }
+            _ = ack_commit_timer.tick(), if ack_on_write => {
+                commit_acked_offsets(&consumer, &acked_offsets)?;
+            }
+            msg = consumer.recv() => match msg {
+                Err(e) => warn!("Kafka error: {}", e),
+                Ok(msg) => {
+                    debug!(
+                        "key: '{:?}', topic: {}, partition: {}, offset: {}, timestamp: {:?}",
+                        msg.key(),
+                        msg.topic(),
+                        msg.partition(),
+                        msg.offset(),
+                        msg.timestamp()
+                    );
+
+                    let is_control_message = *msg.topic() == control_args.control_topic;
+
+                    if let Some(payload) = msg.payload() {
+                        if digitizer_analog_trace_message_buffer_has_identifier(payload) {
+                            // A message has been received from the trace topic.
+                            match root_as_digitizer_analog_trace_message(payload) {
+                                Ok(data) => {
+                                    info!(
+                                        "Trace packet: dig. ID: {}, metadata: {:?}",
+                                        data.digitizer_id(),
+                                        data.metadata()
+                                    );
+                                    counter!(
+                                        MESSAGES_RECEIVED,
+                                        &[messages_received::get_label(MessageKind::Trace)]
+                                    )
+                                    .increment(1);
+
+                                    match digitizer_runs
+                                        .get(&data.digitizer_id())
+                                        .and_then(|run_name| open_files.get_mut(run_name))
+                                    {
+                                        Some(file) => {
+                                            info!("Writing trace data to \"{}\"", file.filename());
+                                            let provenance = TraceProvenance {
+                                                key: msg.key(),
+                                                headers: msg
+                                                    .headers()
+                                                    .map(|headers| {
+                                                        headers
+                                                            .iter()
+                                                            .filter_map(|header| {
+                                                                header.value.map(|value| (header.key, value))
+                                                            })
+                                                            .collect()
+                                                    })
+                                                    .unwrap_or_default(),
+                                                partition: msg.partition(),
+                                                offset: msg.offset(),
+                                                broker_timestamp_millis: msg.timestamp().to_millis(),
+                                            };
+                                            match file.push(&data, &provenance).and_then(|()| file.flush()) {
+                                                Ok(()) if ack_on_write => {
+                                                    ack_offset(&mut acked_offsets, &msg);
+                                                }
+                                                Ok(()) => {}
+                                                Err(e) => {
+                                                    warn!("Failed to save traces to file: {}", e);
+                                                    counter!(
+                                                        FAILURES,
+                                                        &[failures::get_label(FailureKind::FileWriteFailed)]
+                                                    )
+                                                    .increment(1);
+                                                }
+                                            }
+                                        }
+                                        None => {
+                                            debug!(
+                                                "No open run for digitizer {}, discarding trace",
+                                                data.digitizer_id()
+                                            );
+                                            // There is no write to wait for, so there is nothing
+                                            // to gate this offset on; ack it immediately or an
+                                            // idle period with no open run would otherwise stall
+                                            // this partition's committed offset indefinitely.
+                                            if ack_on_write {
+                                                ack_offset(&mut acked_offsets, &msg);
+                                            }
+                                        }
                                     }
                                 }
-                            }
 
-                            Err(e) => {
-                                warn!("Failed to parse message: {}", e);
-                                counter!(
-                                    FAILURES,
-                                    &[failures::get_label(FailureKind::UnableToDecodeMessage)]
-                                )
-                                .increment(1);
-                            }
-                        }
-                    } else if *msg.topic() == control_args.control_topic {
-                        // A message has been received from the control topic.
-                        if run_start_buffer_has_identifier(payload) {
-                            debug!("New run start.");
-                            // Start recording trace data to file.
-                            if file.is_none() {
-                                if let Ok(filename) = generate_filename(msg.timestamp()) {
-                                    file = Some(TraceFile::create(
-                                        &filename,
-                                        common_args.digitizer_count,
-                                    )?);
-                                    debug!("Created new trace file: {:?}", filename);
-                                } else {
-                                    warn!("Failed to create new trace file.");
+                                Err(e) => {
+                                    warn!("Failed to parse message: {}", e);
                                     counter!(
                                         FAILURES,
-                                        &[failures::get_label(FailureKind::FileWriteFailed)]
+                                        &[failures::get_label(FailureKind::UnableToDecodeMessage)]
                                     )
                                     .increment(1);
+                                    // Same rationale as the no-open-run discard above: there is
+                                    // no write to wait for, so ack immediately rather than stall
+                                    // this partition's committed offset on an unparseable message.
+                                    if ack_on_write {
+                                        ack_offset(&mut acked_offsets, &msg);
+                                    }
+                                }
+                            }
+                        } else if is_control_message {
+                            // A message has been received from the control topic.
+                            if run_start_buffer_has_identifier(payload) {
+                                match root_as_run_start_message(payload) {
+                                    Ok(run_start) => {
+                                        let run_name = run_start.run_name().unwrap_or_default().to_owned();
+                                        debug!("New run start: \"{}\"", run_name);
+
+                                        if open_files.contains_key(&run_name) {
+                                            // Run already open; nothing to do.
+                                        } else if open_files.len() >= control_args.max_open_runs {
+                                            warn!(
+                                                "Refusing to start run \"{}\": {} runs already open (max {})",
+                                                run_name,
+                                                open_files.len(),
+                                                control_args.max_open_runs
+                                            );
+                                            counter!(
+                                                FAILURES,
+                                                &[failures::get_label(FailureKind::FileWriteFailed)]
+                                            )
+                                            .increment(1);
+                                        } else if let Ok(filename) =
+                                            generate_filename(msg.timestamp(), &run_name)
+                                        {
+                                            let file = TraceFile::create(
+                                                &filename,
+                                                common_args.digitizer_count,
+                                                Compression {
+                                                    kind: common_args.compression,
+                                                    level: common_args.compression_level,
+                                                },
+                                            )?;
+                                            debug!("Created new trace file: {:?}", filename);
+
+                                            if let Some(digitizer_ids) = run_start.digitizer_ids() {
+                                                for digitizer_id in digitizer_ids {
+                                                    digitizer_runs.insert(digitizer_id, run_name.clone());
+                                                }
+                                            }
+                                            open_files.insert(run_name, file);
+                                            metrics::gauge!(OPEN_RUNS).set(open_files.len() as f64);
+                                        } else {
+                                            warn!("Failed to create new trace file.");
+                                            counter!(
+                                                FAILURES,
+                                                &[failures::get_label(FailureKind::FileWriteFailed)]
+                                            )
+                                            .increment(1);
+                                        }
+                                    }
+                                    Err(e) => warn!("Failed to parse run start message: {}", e),
+                                }
+                            } else if run_stop_buffer_has_identifier(payload) {
+                                match root_as_run_stop_message(payload) {
+                                    Ok(run_stop) => {
+                                        let run_name = run_stop.run_name().unwrap_or_default();
+                                        debug!("New run stop: \"{}\"", run_name);
+
+                                        if let Some(file) = open_files.remove(run_name) {
+                                            digitizer_runs.retain(|_, owner| owner.as_str() != run_name);
+
+                                            // Commit whatever has been acknowledged so far before
+                                            // the file handle backing those offsets is dropped.
+                                            if ack_on_write {
+                                                commit_acked_offsets(&consumer, &acked_offsets)?;
+                                                acked_offsets.clear();
+                                            }
+                                            drop(file);
+                                            metrics::gauge!(OPEN_RUNS).set(open_files.len() as f64);
+                                        } else {
+                                            warn!("Run stop for unknown or already-closed run \"{}\"", run_name);
+                                        }
+                                    }
+                                    Err(e) => warn!("Failed to parse run stop message: {}", e),
                                 }
+                            } else {
+                                warn!("Incorrect message identifier on topic \"{}\"", msg.topic());
                             }
-                            // If file already exists, do nothing.
-                        } else if run_stop_buffer_has_identifier(payload) {
-                            debug!("New run stop.");
-                            // Stop recording trace data to file.
-                            file = None;
                         } else {
-                            warn!("Incorrect message identifier on topic \"{}\"", msg.topic());
+                            // The message kind is unknown.
+                            warn!("Unexpected message type on topic \"{}\"", msg.topic());
+                            counter!(
+                                MESSAGES_RECEIVED,
+                                &[messages_received::get_label(MessageKind::Unknown)]
+                            )
+                            .increment(1);
+                            // Same rationale as the no-open-run discard above.
+                            if ack_on_write {
+                                ack_offset(&mut acked_offsets, &msg);
+                            }
                         }
-                    } else {
-                        // The message kind is unknown.
-                        warn!("Unexpected message type on topic \"{}\"", msg.topic());
-                        counter!(
-                            MESSAGES_RECEIVED,
-                            &[messages_received::get_label(MessageKind::Unknown)]
-                        )
-                        .increment(1);
                     }
-                }
 
-                consumer.commit_message(&msg, CommitMode::Async).unwrap();
+                    // Trace-topic offsets are held back until they are acknowledged as
+                    // durably written (see `acked_offsets` above); control-topic offsets
+                    // are never gated on a file write and are committed immediately.
+                    if !ack_on_write || is_control_message {
+                        consumer.commit_message(&msg, CommitMode::Async).unwrap();
+                    }
+                }
             }
         };
     }
+
+    // Finalize every in-progress file so each is left as valid HDF5, and
+    // make sure every offset we can stand behind is committed before we
+    // exit, so a routine redeploy never produces a corrupt output file.
+    for (run_name, file) in open_files.drain() {
+        file.flush()?;
+        info!(
+            "Closed trace file \"{}\" for run \"{}\" on shutdown",
+            file.filename(),
+            run_name
+        );
+    }
+    if ack_on_write {
+        // Only commit what we can stand behind (`acked_offsets`); the stored
+        // offset librdkafka tracks internally includes messages received but
+        // not yet durably written, and `commit_consumer_state` would commit
+        // those too, losing them on restart.
+        commit_acked_offsets(&consumer, &acked_offsets)?;
+    } else {
+        consumer.commit_consumer_state(CommitMode::Sync)?;
+    }
+
+    Ok(())
+}
+
+/// Resolves once either Ctrl+C or a termination signal (e.g. from `SIGTERM`
+/// sent by an orchestrator during a redeploy) is received.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+/// Records `msg`'s offset as acknowledged for its `(topic, partition)`,
+/// keeping the highest offset seen so far. Used both when a trace has been
+/// durably written and when a trace-topic message is discarded outright
+/// (no open run, parse failure, unrecognised schema) — either way there is
+/// nothing left to gate the offset on.
+fn ack_offset(acked_offsets: &mut HashMap<(String, i32), i64>, msg: &impl Message) {
+    let key = (msg.topic().to_owned(), msg.partition());
+    acked_offsets
+        .entry(key)
+        .and_modify(|offset| *offset = (*offset).max(msg.offset()))
+        .or_insert(msg.offset());
 }
 
-fn generate_filename(timestamp: Timestamp) -> Result<PathBuf> {
+/// Commits the highest acknowledged offset for each `(topic, partition)` in
+/// `acked_offsets`, if any. Kafka commits store the offset of the *next*
+/// message to be consumed, so each stored offset is incremented by one.
+fn commit_acked_offsets(
+    consumer: &StreamConsumer<MetricsConsumerContext>,
+    acked_offsets: &HashMap<(String, i32), i64>,
+) -> Result<()> {
+    if acked_offsets.is_empty() {
+        return Ok(());
+    }
+
+    let mut offsets_to_commit = TopicPartitionList::new();
+    for ((topic, partition), offset) in acked_offsets {
+        offsets_to_commit.add_partition_offset(topic, *partition, Offset::Offset(offset + 1))?;
+    }
+    consumer.commit(&offsets_to_commit, CommitMode::Sync)?;
+    Ok(())
+}
+
+/// Builds the output filename for a new run's HDF5 file.
+///
+/// The run-start timestamp alone is only millisecond-resolution, so two runs
+/// starting in the same millisecond would otherwise collide and the second
+/// `TraceFile::create` would truncate the first run's still-open file.
+/// Including the sanitized run name keeps filenames unique across
+/// concurrently open runs, which is guaranteed by `open_files` being keyed
+/// on run name.
+fn generate_filename(timestamp: Timestamp, run_name: &str) -> Result<PathBuf> {
     //  TODO: Check this unwrap does not cause any issues.
     if let Some(timestamp) = timestamp.to_millis() {
         if let Some(timestamp) = DateTime::<Utc>::from_timestamp_millis(timestamp) {
-            return Ok(PathBuf::from(format!("{:?}.h5", timestamp)));
+            return Ok(PathBuf::from(format!(
+                "{:?}_{}.h5",
+                timestamp,
+                sanitize_run_name(run_name)
+            )));
         }
     }
     Err(anyhow::anyhow!(
         "Failed to convert timestamp to milliseconds"
     ))
 }
+
+/// Replaces any character unsafe in a filename with `_`, so a run name
+/// cannot escape the output directory or otherwise produce an invalid path.
+fn sanitize_run_name(run_name: &str) -> String {
+    run_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
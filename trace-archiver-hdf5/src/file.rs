@@ -0,0 +1,174 @@
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::Result;
+use hdf5::File;
+use supermusr_streaming_types::dat2_digitizer_analog_trace_v2_generated::DigitizerAnalogTraceMessage;
+use tracing::warn;
+
+use crate::CompressionKind;
+
+/// Compression filter and level applied to every trace dataset written by a
+/// [`TraceFile`].
+#[derive(Clone, Copy)]
+pub(crate) struct Compression {
+    pub(crate) kind: CompressionKind,
+    pub(crate) level: u8,
+}
+
+/// Kafka message provenance captured alongside a trace frame, so that a
+/// written frame can be traced back to the exact message that produced it
+/// (e.g. when debugging dropped or duplicated frames across partitions).
+pub(crate) struct TraceProvenance<'a> {
+    pub(crate) key: Option<&'a [u8]>,
+    pub(crate) headers: Vec<(&'a str, &'a [u8])>,
+    pub(crate) partition: i32,
+    pub(crate) offset: i64,
+    pub(crate) broker_timestamp_millis: Option<i64>,
+}
+
+/// Thin wrapper around an open HDF5 file that trace frames are appended to
+/// as they arrive from Kafka.
+///
+/// Each call to [`TraceFile::push`] writes one frame as a new group
+/// containing one dataset per channel.
+pub(crate) struct TraceFile {
+    file: File,
+    filename: PathBuf,
+    digitizer_count: usize,
+    frames_written: usize,
+    compression: Compression,
+}
+
+impl TraceFile {
+    pub(crate) fn create(
+        filename: &Path,
+        digitizer_count: usize,
+        compression: Compression,
+    ) -> Result<Self> {
+        let file = File::create(filename)?;
+        Ok(Self {
+            file,
+            filename: filename.to_owned(),
+            digitizer_count,
+            frames_written: 0,
+            compression,
+        })
+    }
+
+    pub(crate) fn filename(&self) -> String {
+        self.filename.to_string_lossy().into_owned()
+    }
+
+    pub(crate) fn push(
+        &mut self,
+        data: &DigitizerAnalogTraceMessage,
+        provenance: &TraceProvenance,
+    ) -> Result<()> {
+        if data.digitizer_id() as usize >= self.digitizer_count {
+            warn!(
+                "Digitizer ID {} is outside the expected range of {} digitizers for this run",
+                data.digitizer_id(),
+                self.digitizer_count
+            );
+        }
+
+        let frame_number = data
+            .metadata()
+            .map(|m| m.frame_number())
+            .unwrap_or(self.frames_written as u32);
+
+        let group = self.file.create_group(&format!(
+            "frame_{frame_number}_digitizer_{}",
+            data.digitizer_id()
+        ))?;
+
+        if let Some(channels) = data.channels() {
+            for channel in channels {
+                let Some(voltage) = channel.voltage() else {
+                    continue;
+                };
+                let voltage: Vec<_> = voltage.iter().collect();
+                // Chunk shape tuned to a single frame: every write is exactly one
+                // frame, so this keeps each chunk fully populated and lets
+                // downstream readers fetch one frame without touching others.
+                let builder = group
+                    .new_dataset_builder()
+                    .with_data(&voltage)
+                    .chunk(voltage.len());
+                let builder = match self.compression.kind {
+                    CompressionKind::None => builder,
+                    CompressionKind::Gzip => builder.deflate(self.compression.level),
+                    CompressionKind::Zstd => builder.blosc_zstd(self.compression.level, true),
+                };
+                builder.create(format!("channel_{}", channel.channel()).as_str())?;
+            }
+        }
+
+        self.write_provenance(&group, provenance)?;
+
+        self.frames_written += 1;
+        Ok(())
+    }
+
+    /// Records the Kafka message metadata a frame was read from as HDF5
+    /// attributes on that frame's group, with the message headers (if any)
+    /// as a `kafka_headers` dataset in the same group, so the written data
+    /// can always be traced back to the message that produced it.
+    fn write_provenance(&self, group: &hdf5::Group, provenance: &TraceProvenance) -> Result<()> {
+        let key = provenance
+            .key
+            .map(|key| String::from_utf8_lossy(key).into_owned())
+            .unwrap_or_default();
+        group
+            .new_attr_builder()
+            .with_data(&hdf5::types::VarLenUnicode::from_str(&key)?)
+            .create("kafka_key")?;
+        group
+            .new_attr_builder()
+            .with_data(&provenance.partition)
+            .create("kafka_partition")?;
+        group
+            .new_attr_builder()
+            .with_data(&provenance.offset)
+            .create("kafka_offset")?;
+        if let Some(timestamp) = provenance.broker_timestamp_millis {
+            group
+                .new_attr_builder()
+                .with_data(&timestamp)
+                .create("kafka_broker_timestamp_ms")?;
+        }
+
+        if !provenance.headers.is_empty() {
+            let headers: Vec<_> = provenance
+                .headers
+                .iter()
+                .map(|(key, value)| {
+                    hdf5::types::VarLenUnicode::from_str(&format!(
+                        "{key}={}",
+                        String::from_utf8_lossy(value)
+                    ))
+                })
+                .collect::<std::result::Result<_, _>>()?;
+            group
+                .new_dataset_builder()
+                .with_data(&headers)
+                .create("kafka_headers")?;
+        }
+
+        Ok(())
+    }
+
+    /// Flushes any buffered writes to disk, making them durable.
+    ///
+    /// This is distinct from [`TraceFile::push`] succeeding: HDF5 may keep
+    /// newly written datasets in its own internal buffers until a flush is
+    /// requested, so callers that need a durability guarantee (e.g. before
+    /// advancing a Kafka commit offset) must call this explicitly.
+    pub(crate) fn flush(&self) -> Result<()> {
+        self.file.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+mod context;
+mod control;
+mod file;
+
+use std::net::SocketAddr;
+
+use clap::{Parser, ValueEnum};
+
+/// HDF5 filter pipeline to apply to trace datasets.
+#[derive(Clone, Copy, ValueEnum)]
+pub(crate) enum CompressionKind {
+    /// No compression filter.
+    None,
+    /// Deflate (gzip), widely supported but slower than zstd at a given ratio.
+    Gzip,
+    /// Blosc-zstd, better throughput than gzip at a comparable ratio.
+    Zstd,
+}
+
+/// Options shared by every consumer-side entry point in this crate.
+#[derive(Clone, Parser)]
+pub(crate) struct CommonOpts {
+    /// Kafka broker address
+    #[clap(long)]
+    pub(crate) broker: String,
+
+    /// Kafka username
+    #[clap(long)]
+    pub(crate) username: String,
+
+    /// Kafka password
+    #[clap(long)]
+    pub(crate) password: String,
+
+    /// Topic to consume analog trace packets from
+    #[clap(long)]
+    pub(crate) trace_topic: String,
+
+    /// Kafka consumer group
+    #[clap(long)]
+    pub(crate) consumer_group: String,
+
+    /// Number of digitizers expected to contribute to each frame
+    #[clap(long, default_value = "8")]
+    pub(crate) digitizer_count: usize,
+
+    /// Address to serve Prometheus metrics on
+    #[clap(long, default_value = "127.0.0.1:9090")]
+    pub(crate) observability_address: SocketAddr,
+
+    /// Kafka transport security protocol: plaintext, ssl, sasl_plaintext, sasl_ssl
+    #[clap(long, default_value = "sasl_plaintext")]
+    pub(crate) security_protocol: String,
+
+    /// SASL mechanism, e.g. SCRAM-SHA-256, PLAIN, GSSAPI
+    #[clap(long, default_value = "SCRAM-SHA-256")]
+    pub(crate) sasl_mechanism: String,
+
+    /// Path to the CA certificate used to verify the broker's certificate
+    #[clap(long)]
+    pub(crate) ssl_ca_location: Option<String>,
+
+    /// Path to the client certificate presented to the broker
+    #[clap(long)]
+    pub(crate) ssl_certificate_location: Option<String>,
+
+    /// Path to the client private key matching `--ssl-certificate-location`
+    #[clap(long)]
+    pub(crate) ssl_key_location: Option<String>,
+
+    /// Password protecting the private key at `--ssl-key-location`
+    #[clap(long)]
+    pub(crate) ssl_key_password: Option<String>,
+
+    /// Compression filter applied to trace datasets
+    #[clap(long, value_enum, default_value = "none")]
+    pub(crate) compression: CompressionKind,
+
+    /// Compression level passed to the chosen `--compression` filter
+    #[clap(long, default_value = "6")]
+    pub(crate) compression_level: u8,
+}
+
+#[derive(Parser)]
+#[clap(author, version, about)]
+pub(crate) struct ControlOpts {
+    #[clap(flatten)]
+    pub(crate) common: CommonOpts,
+
+    /// Topic carrying run-start/run-stop control messages
+    #[clap(long)]
+    pub(crate) control_topic: String,
+
+    /// Hold back Kafka offset commits for trace messages until the data has
+    /// been durably flushed to its run's HDF5 file, instead of committing
+    /// immediately on receipt; trace messages that are discarded without a
+    /// write are acknowledged at once. Prevents loss of unwritten traces on restart,
+    /// at the cost of redelivering messages that were received but not yet
+    /// acknowledged. Control-topic messages are unaffected and are always
+    /// committed immediately.
+    #[clap(long)]
+    pub(crate) ack_on_write: bool,
+
+    /// Maximum number of runs that may have an HDF5 file open at once.
+    /// Additional concurrent run-starts are rejected until an existing run
+    /// stops and frees a slot.
+    #[clap(long, default_value = "4")]
+    pub(crate) max_open_runs: usize,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let control_args = ControlOpts::parse();
+    control::run(control_args).await
+}
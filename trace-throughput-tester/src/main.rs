@@ -3,6 +3,7 @@ use clap::Parser;
 use common::Intensity;
 use rdkafka::{
     config::ClientConfig,
+    message::{Header, OwnedHeaders},
     producer::{FutureProducer, FutureRecord},
     util::Timeout,
 };
@@ -17,6 +18,11 @@ use streaming_types::{
 };
 use tokio::time;
 
+/// Flatbuffer schema identifier for `DigitizerAnalogTraceMessage` v1, stamped
+/// into each message's Kafka headers so consumers can tell which schema
+/// produced a given trace without decoding the payload.
+const SCHEMA_ID: &str = "dat1";
+
 #[derive(Clone, Parser)]
 #[clap(author, version, about)]
 struct Cli {
@@ -51,6 +57,30 @@ struct Cli {
     /// Time in milliseconds between each frame
     #[clap(long, default_value = "20")]
     frame_time: u64,
+
+    /// Kafka transport security protocol: plaintext, ssl, sasl_plaintext, sasl_ssl
+    #[clap(long, default_value = "sasl_plaintext")]
+    security_protocol: String,
+
+    /// SASL mechanism, e.g. SCRAM-SHA-256, PLAIN, GSSAPI
+    #[clap(long, default_value = "SCRAM-SHA-256")]
+    sasl_mechanism: String,
+
+    /// Path to the CA certificate used to verify the broker's certificate
+    #[clap(long)]
+    ssl_ca_location: Option<String>,
+
+    /// Path to the client certificate presented to the broker
+    #[clap(long)]
+    ssl_certificate_location: Option<String>,
+
+    /// Path to the client private key matching `--ssl-certificate-location`
+    #[clap(long)]
+    ssl_key_location: Option<String>,
+
+    /// Password protecting the private key at `--ssl-key-location`
+    #[clap(long)]
+    ssl_key_password: Option<String>,
 }
 
 #[tokio::main]
@@ -59,14 +89,32 @@ async fn main() {
 
     let cli = Cli::parse();
 
-    let producer: FutureProducer = ClientConfig::new()
+    let mut client_config = ClientConfig::new();
+    client_config
         .set("bootstrap.servers", &cli.broker_address)
-        .set("security.protocol", "sasl_plaintext")
-        .set("sasl.mechanisms", "SCRAM-SHA-256")
+        .set("security.protocol", &cli.security_protocol)
+        .set("sasl.mechanisms", &cli.sasl_mechanism)
         .set("sasl.username", &cli.username)
-        .set("sasl.password", &cli.password)
-        .create()
-        .unwrap();
+        .set("sasl.password", &cli.password);
+
+    if let Some(ca_location) = &cli.ssl_ca_location {
+        client_config.set("ssl.ca.location", ca_location);
+    }
+    if let Some(certificate_location) = &cli.ssl_certificate_location {
+        client_config.set("ssl.certificate.location", certificate_location);
+    }
+    if let Some(key_location) = &cli.ssl_key_location {
+        client_config.set("ssl.key.location", key_location);
+    }
+    if let Some(key_password) = &cli.ssl_key_password {
+        client_config.set("ssl.key.password", key_password);
+    }
+
+    let producer: FutureProducer = client_config.create().unwrap();
+
+    let producer_hostname = hostname::get()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| "unknown".to_string());
 
     let mut fbb = FlatBufferBuilder::new();
 
@@ -126,11 +174,32 @@ async fn main() {
 
         let start_time = SystemTime::now();
 
+        let key = format!("{}_{}", cli.digitizer_id, frame_number);
+        let send_timestamp_ms = start_time
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis()
+            .to_string();
+        let headers = OwnedHeaders::new()
+            .insert(Header {
+                key: "producer-hostname",
+                value: Some(&producer_hostname),
+            })
+            .insert(Header {
+                key: "schema-id",
+                value: Some(SCHEMA_ID),
+            })
+            .insert(Header {
+                key: "send-timestamp-ms",
+                value: Some(&send_timestamp_ms),
+            });
+
         match producer
             .send(
                 FutureRecord::to(&cli.trace_topic)
                     .payload(fbb.finished_data())
-                    .key(&"todo".to_string()),
+                    .key(&key)
+                    .headers(headers),
                 Timeout::After(Duration::from_millis(100)),
             )
             .await